@@ -0,0 +1,392 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2016 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A rolling buffer of fragmented-MP4 (`moof`/`mdat`) segments per camera, for low-latency
+//! live viewing (LL-HLS / fMP4-over-HTTP) of a stream that's simultaneously being recorded to
+//! `dir::SampleFileDir` in Moonfire's internal format.
+
+use error::Error;
+use recording;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Number of recent fragments retained per camera; old ones are dropped once this fills up.
+const MAX_LIVE_FRAGMENTS: usize = 4;
+
+/// The `track_ID` used throughout the init segment and every fragment's `moof`. There's only
+/// ever one track (video) per camera feed, so a fixed id is fine; it just has to agree between
+/// `tkhd`, `trex`, and `tfhd`.
+const TRACK_ID: u32 = 1;
+
+/// Row-major unity matrix for `tkhd`/`mvhd` (ISO/IEC 14496-12 section 8.3.2.3): the `u`, `v` and
+/// `w` entries are 2.30 fixed point, the rest 16.16 fixed point.
+const UNITY_MATRIX: [u8; 36] = [
+    0x00, 0x01, 0x00, 0x00,  0, 0, 0, 0,  0, 0, 0, 0,
+    0, 0, 0, 0,  0x00, 0x01, 0x00, 0x00,  0, 0, 0, 0,
+    0, 0, 0, 0,  0, 0, 0, 0,  0x40, 0x00, 0x00, 0x00,
+];
+
+fn write_box(fourcc: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(8 + body.len());
+    b.extend_from_slice(&((8 + body.len()) as u32).to_be_bytes());
+    b.extend_from_slice(fourcc);
+    b.extend_from_slice(body);
+    b
+}
+
+/// A single finalized fragment: one `moof` box describing its samples, followed by the `mdat`
+/// box holding the sample data itself.
+pub struct Fragment {
+    /// Capture time of this fragment's first (key) frame.
+    pub start: recording::Time,
+
+    /// Total duration of the fragment, in `recording::TIME_UNITS_PER_SEC` units.
+    pub duration_90k: i32,
+
+    /// The encoded `moof` + `mdat` boxes, ready to be appended to a client's byte stream.
+    pub data: Vec<u8>,
+}
+
+struct PendingSample {
+    data: Vec<u8>,
+    duration_90k: i32,
+    is_key: bool,
+}
+
+struct CameraFeed {
+    sample_entry: Vec<u8>,
+    init_segment: Vec<u8>,
+    sequence_number: u32,
+    base_media_decode_time: i64,
+    fragment_start: Option<recording::Time>,
+    pending: Vec<PendingSample>,
+    last_pts: Option<i64>,
+    fragments: VecDeque<Arc<Fragment>>,
+}
+
+impl CameraFeed {
+    fn new(width: u16, height: u16, sample_entry: &[u8]) -> Self {
+        CameraFeed{
+            sample_entry: sample_entry.to_owned(),
+            init_segment: build_init_segment(width, height, sample_entry),
+            sequence_number: 0,
+            base_media_decode_time: 0,
+            fragment_start: None,
+            pending: Vec::new(),
+            last_pts: None,
+            fragments: VecDeque::new(),
+        }
+    }
+
+    /// Closes out the in-progress fragment (if any complete samples are buffered) and pushes it
+    /// onto the rolling `fragments` deque, trimming the oldest if it overflows.
+    fn finalize_fragment(&mut self) -> Result<(), Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let start = self.fragment_start.ok_or_else(
+            || Error::new("fragment has samples but no start time".to_owned()))?;
+        self.sequence_number += 1;
+        let total_duration_90k: i32 = self.pending.iter().map(|s| s.duration_90k).sum();
+        let moof = build_moof(self.sequence_number, self.base_media_decode_time, &self.pending);
+        let mdat = build_mdat(&self.pending);
+        let mut data = Vec::with_capacity(moof.len() + mdat.len());
+        data.extend_from_slice(&moof);
+        data.extend_from_slice(&mdat);
+        self.fragments.push_back(Arc::new(Fragment{
+            start: start,
+            duration_90k: total_duration_90k,
+            data: data,
+        }));
+        while self.fragments.len() > MAX_LIVE_FRAGMENTS {
+            self.fragments.pop_front();
+        }
+        self.base_media_decode_time += total_duration_90k as i64;
+        self.pending.clear();
+        self.fragment_start = None;
+        Ok(())
+    }
+}
+
+fn build_init_segment(width: u16, height: u16, sample_entry: &[u8]) -> Vec<u8> {
+    let ftyp = write_box(b"ftyp", &{
+        let mut b = Vec::new();
+        b.extend_from_slice(b"isom");           // major_brand
+        b.extend_from_slice(&[0, 0, 0, 1]);     // minor_version
+        b.extend_from_slice(b"isom");
+        b.extend_from_slice(b"iso6");
+        b
+    });
+    let mvhd = write_box(b"mvhd", &{
+        let mut b = vec![0u8; 4]; // version/flags
+        b.extend_from_slice(&[0; 8]);  // creation/modification time
+        b.extend_from_slice(&recording::TIME_UNITS_PER_SEC.to_be_bytes()[4..8]); // timescale
+        b.extend_from_slice(&[0; 4]);  // duration (unknown, live)
+        b.extend_from_slice(&[0, 1, 0, 0]); // rate 1.0
+        b.extend_from_slice(&[0, 1]);  // volume 1.0
+        b.extend_from_slice(&[0; 2]);  // reserved
+        b.extend_from_slice(&[0; 8]);  // reserved
+        b.extend_from_slice(&UNITY_MATRIX);
+        b.extend_from_slice(&[0; 24]); // pre_defined
+        b.extend_from_slice(&(TRACK_ID + 1).to_be_bytes()); // next_track_ID
+        b
+    });
+    let tkhd = write_box(b"tkhd", &{
+        let mut b = vec![0u8, 0, 0, 7]; // version 0, flags: enabled | in_movie | in_preview
+        b.extend_from_slice(&[0; 8]);  // creation/modification time
+        b.extend_from_slice(&TRACK_ID.to_be_bytes());
+        b.extend_from_slice(&[0; 4]);  // reserved
+        b.extend_from_slice(&[0; 4]);  // duration (unknown, live)
+        b.extend_from_slice(&[0; 8]);  // reserved
+        b.extend_from_slice(&[0; 2]);  // layer
+        b.extend_from_slice(&[0; 2]);  // alternate_group
+        b.extend_from_slice(&[0; 2]);  // volume (0 for a video track)
+        b.extend_from_slice(&[0; 2]);  // reserved
+        b.extend_from_slice(&UNITY_MATRIX);
+        b.extend_from_slice(&[(width >> 8) as u8, width as u8, 0, 0]);   // width, 16.16 fixed
+        b.extend_from_slice(&[(height >> 8) as u8, height as u8, 0, 0]); // height, 16.16 fixed
+        b
+    });
+    let mdhd = write_box(b"mdhd", &{
+        let mut b = vec![0u8; 4]; // version/flags
+        b.extend_from_slice(&[0; 8]);  // creation/modification time
+        b.extend_from_slice(&recording::TIME_UNITS_PER_SEC.to_be_bytes()[4..8]); // timescale
+        b.extend_from_slice(&[0; 4]);  // duration (unknown, live)
+        b.extend_from_slice(&[0x55, 0xc4]); // language "und"
+        b.extend_from_slice(&[0; 2]);  // pre_defined
+        b
+    });
+    let hdlr = write_box(b"hdlr", &{
+        let mut b = vec![0u8; 4]; // version/flags
+        b.extend_from_slice(&[0; 4]);  // pre_defined
+        b.extend_from_slice(b"vide"); // handler_type
+        b.extend_from_slice(&[0; 12]); // reserved
+        b.extend_from_slice(b"VideoHandler\0"); // name
+        b
+    });
+    let stsd = write_box(b"stsd", &{
+        let mut b = vec![0u8; 4]; // version/flags
+        b.extend_from_slice(&[0, 0, 0, 1]); // entry_count
+        b.extend_from_slice(sample_entry);
+        b
+    });
+    let stbl = write_box(b"stbl", &stsd);
+    let minf = write_box(b"minf", &stbl);
+    let mut mdia_body = Vec::new();
+    mdia_body.extend_from_slice(&mdhd);
+    mdia_body.extend_from_slice(&hdlr);
+    mdia_body.extend_from_slice(&minf);
+    let mdia = write_box(b"mdia", &mdia_body);
+    let trex = write_box(b"trex", &{
+        let mut b = vec![0u8; 4]; // version/flags
+        b.extend_from_slice(&TRACK_ID.to_be_bytes());
+        b.extend_from_slice(&[0, 0, 0, 1]); // default_sample_description_index
+        b.extend_from_slice(&[0; 12]);      // default duration/size/flags
+        b
+    });
+    let mvex = write_box(b"mvex", &trex);
+    let mut trak_body = Vec::new();
+    trak_body.extend_from_slice(&tkhd);
+    trak_body.extend_from_slice(&mdia);
+    let trak = write_box(b"trak", &trak_body);
+    let mut moov_body = Vec::new();
+    moov_body.extend_from_slice(&mvhd);
+    moov_body.extend_from_slice(&trak);
+    moov_body.extend_from_slice(&mvex);
+    let moov = write_box(b"moov", &moov_body);
+    let mut out = Vec::with_capacity(ftyp.len() + moov.len());
+    out.extend_from_slice(&ftyp);
+    out.extend_from_slice(&moov);
+    out
+}
+
+/// Builds a `moof` box for the given batch of samples, with `tfdt` set from the accumulated
+/// duration of all previously emitted fragments so client timelines stay contiguous.
+fn build_moof(sequence_number: u32, base_media_decode_time: i64,
+              samples: &[PendingSample]) -> Vec<u8> {
+    let mfhd = write_box(b"mfhd", &{
+        let mut b = vec![0u8; 4];
+        b.extend_from_slice(&sequence_number.to_be_bytes());
+        b
+    });
+    let tfhd = write_box(b"tfhd", &{
+        let mut b = vec![0u8; 4];
+        b.extend_from_slice(&TRACK_ID.to_be_bytes());
+        b
+    });
+    let tfdt = write_box(b"tfdt", &{
+        let mut b = vec![1u8, 0, 0, 0]; // version=1 (64-bit base_media_decode_time)
+        b.extend_from_slice(&base_media_decode_time.to_be_bytes());
+        b
+    });
+    let trun = write_box(b"trun", &{
+        let mut b = vec![0u8, 0, 3, 0]; // flags: sample-duration + sample-size present
+        b.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+        for s in samples {
+            b.extend_from_slice(&s.duration_90k.to_be_bytes());
+            b.extend_from_slice(&(s.data.len() as u32).to_be_bytes());
+        }
+        b
+    });
+    let mut traf_body = Vec::new();
+    traf_body.extend_from_slice(&tfhd);
+    traf_body.extend_from_slice(&tfdt);
+    traf_body.extend_from_slice(&trun);
+    let traf = write_box(b"traf", &traf_body);
+    let mut moof_body = Vec::new();
+    moof_body.extend_from_slice(&mfhd);
+    moof_body.extend_from_slice(&traf);
+    write_box(b"moof", &moof_body)
+}
+
+fn build_mdat(samples: &[PendingSample]) -> Vec<u8> {
+    let total: usize = samples.iter().map(|s| s.data.len()).sum();
+    let mut body = Vec::with_capacity(total);
+    for s in samples {
+        body.extend_from_slice(&s.data);
+    }
+    write_box(b"mdat", &body)
+}
+
+/// A per-camera handle into `LiveSegments`, used by `Streamer::run_once` to publish samples as
+/// they're captured.
+pub struct FeedHandle {
+    feed: Arc<Mutex<CameraFeed>>,
+}
+
+impl FeedHandle {
+    /// Publishes one encoded sample, keying fragment boundaries off key frames the same way
+    /// recording rotation does.
+    pub fn push_sample(&mut self, data: &[u8], pts: i64, capture_time: recording::Time,
+                       is_key: bool) -> Result<(), Error> {
+        let mut f = self.feed.lock().unwrap();
+        if let Some(last_pts) = f.last_pts {
+            if let Some(last) = f.pending.last_mut() {
+                last.duration_90k = (pts - last_pts) as i32;
+            }
+        }
+        if is_key {
+            f.finalize_fragment()?;
+            f.fragment_start = Some(capture_time);
+        }
+        if f.fragment_start.is_none() {
+            // A non-key sample before any key frame has been seen; nothing to anchor a
+            // fragment to yet, so just drop it (mirrors `Streamer`'s own "wait for first key
+            // frame" behavior).
+            return Ok(());
+        }
+        f.pending.push(PendingSample{data: data.to_owned(), duration_90k: 0, is_key: is_key});
+        f.last_pts = Some(pts);
+        Ok(())
+    }
+
+    /// Returns the current init segment (`ftyp`/`moov`) and the rolling set of recent
+    /// fragments, for an HTTP handler to serve to a new client.
+    pub fn snapshot(&self) -> (Vec<u8>, Vec<Arc<Fragment>>) {
+        let f = self.feed.lock().unwrap();
+        (f.init_segment.clone(), f.fragments.iter().cloned().collect())
+    }
+}
+
+/// Shared registry of live fMP4 feeds, one per camera, for all `Streamer` threads to publish
+/// into and the HTTP server to read from.
+pub struct LiveSegments {
+    feeds: Mutex<HashMap<i32, Arc<Mutex<CameraFeed>>>>,
+}
+
+impl LiveSegments {
+    pub fn new() -> Self {
+        LiveSegments{feeds: Mutex::new(HashMap::new())}
+    }
+
+    /// Registers (or re-registers, after a sample entry change) the feed for `camera_id`,
+    /// resetting its init segment to match `sample_entry`.
+    pub fn register(&self, camera_id: i32, width: u16, height: u16,
+                    sample_entry: &[u8]) -> FeedHandle {
+        let feed = Arc::new(Mutex::new(CameraFeed::new(width, height, sample_entry)));
+        self.feeds.lock().unwrap().insert(camera_id, feed.clone());
+        FeedHandle{feed: feed}
+    }
+
+    /// Looks up the current feed for `camera_id`, for an HTTP handler to `snapshot()` from.
+    /// Returns `None` if that camera's `Streamer` hasn't registered a feed yet (e.g. it hasn't
+    /// finished opening its stream for the first time).
+    pub fn get(&self, camera_id: i32) -> Option<FeedHandle> {
+        self.feeds.lock().unwrap().get(&camera_id).map(|feed| FeedHandle{feed: feed.clone()})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use recording;
+    use testutil;
+
+    #[test]
+    fn fragments_start_on_key_frames_and_roll_off() {
+        testutil::init();
+        let live = super::LiveSegments::new();
+        let mut feed = live.register(1, 320, 240, &[0u8; 4]);
+        let t0 = recording::Time(0);
+
+        // A leading non-key sample before any key frame is dropped.
+        feed.push_sample(b"nonkey", 0, t0, false).unwrap();
+        let (_, frags) = feed.snapshot();
+        assert_eq!(frags.len(), 0);
+
+        // Each key frame starts a new fragment; pushing more than MAX_LIVE_FRAGMENTS trims the
+        // oldest ones.
+        for i in 0..6 {
+            let pts = i * recording::TIME_UNITS_PER_SEC;
+            let t = recording::Time(pts);
+            feed.push_sample(b"key", pts, t, true).unwrap();
+            feed.push_sample(b"p-frame", pts + 1000, t, false).unwrap();
+        }
+        let (init, frags) = feed.snapshot();
+        assert!(!init.is_empty());
+        assert!(frags.len() <= super::MAX_LIVE_FRAGMENTS);
+        assert!(frags.len() >= 1);
+    }
+
+    #[test]
+    fn get_returns_the_registered_feed_and_none_otherwise() {
+        testutil::init();
+        let live = super::LiveSegments::new();
+        assert!(live.get(1).is_none());
+        let mut feed = live.register(1, 320, 240, &[0u8; 4]);
+        let t0 = recording::Time(0);
+        feed.push_sample(b"key", 0, t0, true).unwrap();
+
+        let looked_up = live.get(1).expect("feed should be registered");
+        let (init, _) = looked_up.snapshot();
+        assert_eq!(init, feed.snapshot().0);
+        assert!(live.get(2).is_none());
+    }
+}
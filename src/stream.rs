@@ -0,0 +1,68 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2016 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Traits implemented by the concrete (ffmpeg-backed) RTSP stream reader. This file defines only
+//! the contract `Streamer` programs against; it does not define `FfmpegStream`/`FFMPEG`, the
+//! production implementation used by the tests in `streamer.rs`.
+
+use error::Error;
+use h264;
+
+/// One RTP/NTP anchor point from an RTCP sender report (RFC 3550 section 6.4.1), used to map a
+/// stream's RTP timestamp timeline to wall-clock time.
+#[derive(Copy, Clone, Debug)]
+pub struct SenderReport {
+    /// The RTP timestamp, in the stream's media clock rate, corresponding to `ntp_timestamp`.
+    pub rtp_ts: i64,
+
+    /// The wall-clock time of `rtp_ts`, as a 64-bit NTP timestamp: the high 32 bits are seconds
+    /// since the NTP epoch (1900-01-01), the low 32 bits are the binary fraction of a second.
+    pub ntp_timestamp: u64,
+}
+
+pub enum Source<'a> {
+    Rtsp(&'a str),
+    File(&'a str),
+}
+
+pub trait Stream {
+    fn get_next(&mut self) -> Result<::ffmpeg::Packet, ::ffmpeg::Error>;
+    fn get_extra_data(&self) -> Result<h264::ExtraData, Error>;
+
+    /// Returns and clears the most recently received RTCP sender report for this stream, if any
+    /// has arrived since the last call. Streams that don't expose sender reports (or haven't
+    /// received one yet) return `None`; callers must keep using local arrival time until the
+    /// first one shows up.
+    fn take_sender_report(&mut self) -> Option<SenderReport> { None }
+}
+
+pub trait Opener<S: Stream> {
+    fn open(&self, src: Source) -> Result<S, Error>;
+}